@@ -3,8 +3,10 @@ use std::collections::HashMap;
 use std::io::{BufReader, BufWriter, Read, Write};
 use std::path::Path;
 use std::process::ExitCode;
-use image;
-use clap::{Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
 use thiserror::Error;
 
 /// Errors from Picbin
@@ -26,6 +28,19 @@ pub enum PicbinError {
     /// Generic imaging error
     #[error("Image Error: {0}")]
     Imaging(#[from] image::error::ImageError),
+
+    /// The image does not contain a valid Picbin header
+    #[error("not a picbin image, or the header is corrupt")]
+    InvalidHeader,
+
+    /// The payload's CRC32 does not match the checksum stored in the header
+    #[error("checksum mismatch; the payload is corrupt")]
+    ChecksumMismatch,
+
+    /// The chosen output format can't survive the exact-match round trip
+    #[error("{0:?} is a lossy format; the exact-match payload can't survive it \
+             — pass --block-size or pick a lossless format")]
+    LossyFormatUnsupported(image::ImageFormat),
 }
 
 /// Command line arguments structure
@@ -50,6 +65,24 @@ enum Commands {
         bin: String,
         /// Path to the resulting image file
         dst: String,
+        /// Pack three payload bytes into each pixel's R, G and B channels
+        /// instead of one byte per pixel, for a 3x smaller image
+        #[arg(long)]
+        packed: bool,
+        /// Write each payload byte as an NxN square of identical pixels
+        /// instead of a single pixel, so the image survives moderate JPEG
+        /// compression and downscaling. Ignored together with --packed.
+        #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u8).range(1..=255))]
+        block_size: u8,
+        /// DEFLATE-compress the input before mapping it to pixels, to
+        /// shrink the resulting image
+        #[arg(long)]
+        compress: bool,
+        /// Explicit output codec; by default it's inferred from `dst`'s
+        /// extension. Lossy codecs are rejected unless --block-size makes
+        /// the payload tolerant of recompression
+        #[arg(long, value_enum)]
+        format: Option<OutputFormat>,
     },
     /// Decode from an image
     Decode {
@@ -57,24 +90,199 @@ enum Commands {
         img: String,
         /// Path to extract the original binary into
         dst: String,
+        /// Decode each pixel to the closest known color instead of
+        /// requiring an exact match, to survive lossy formats and
+        /// resampling. Block-encoded payloads are always decoded this way
+        /// (from each block's average); the flag still governs the header
+        #[arg(long)]
+        nearest: bool,
     },
     /// Print color chart
     ColorChart,
 }
 
+/// Explicit output codecs accepted by `--format`.
+#[derive(Clone, Copy, ValueEnum)]
+enum OutputFormat {
+    Png,
+    Bmp,
+    Tiff,
+    WebpLossless,
+    Jpeg,
+}
+
+impl OutputFormat {
+    fn image_format(self) -> image::ImageFormat {
+        match self {
+            OutputFormat::Png => image::ImageFormat::Png,
+            OutputFormat::Bmp => image::ImageFormat::Bmp,
+            OutputFormat::Tiff => image::ImageFormat::Tiff,
+            OutputFormat::WebpLossless => image::ImageFormat::WebP,
+            OutputFormat::Jpeg => image::ImageFormat::Jpeg,
+        }
+    }
+}
+
+/// Whether `fmt` preserves pixels exactly, as required by the exact-match
+/// encoding modes. Allowlisted rather than denylisted: `dst`'s extension can
+/// resolve to codecs (e.g. GIF, which quantizes to a 256-color palette) that
+/// never go through `OutputFormat` at all, so anything not known-lossless
+/// must be treated as lossy.
+fn image_format_is_lossless(fmt: image::ImageFormat) -> bool {
+    matches!(
+        fmt,
+        image::ImageFormat::Png
+            | image::ImageFormat::Bmp
+            | image::ImageFormat::Tiff
+            | image::ImageFormat::WebP
+    )
+}
+
+/// Magic marker identifying a Picbin header.
+const HEADER_MAGIC: [u8; 4] = *b"PCB1";
+
+/// Serialized size of the header, in bytes (and thus pixels).
+const HEADER_LEN: usize = 18;
+
+/// Bit in the header's flags byte recording the packed raw-RGB mode.
+const FLAG_PACKED: u8 = 1 << 0;
+
+/// Bit in the header's flags byte recording that the payload is
+/// DEFLATE-compressed.
+const FLAG_COMPRESSED: u8 = 1 << 1;
+
+/// Build the CRC32 lookup table using the standard reflected polynomial
+/// `0xEDB88320`.
+const fn crc32_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut n = 0;
+    while n < 256 {
+        let mut a = n as u32;
+        let mut k = 0;
+        while k < 8 {
+            a = if a & 1 == 1 { 0xEDB88320 ^ (a >> 1) } else { a >> 1 };
+            k += 1;
+        }
+        table[n] = a;
+        n += 1;
+    }
+    table
+}
+
+const CRC32_TABLE: [u32; 256] = crc32_table();
+
+/// Compute the CRC32 checksum of `data`.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFFFFFFu32;
+    for &b in data {
+        crc = (crc >> 8) ^ CRC32_TABLE[((crc ^ b as u32) & 0xFF) as usize];
+    }
+    crc ^ 0xFFFFFFFF
+}
+
+/// Fixed-size header stored in the first pixels of an encoded image,
+/// describing how to reconstruct the original payload.
+struct Header {
+    /// Exact length of the stored payload, in bytes (after compression,
+    /// if any; the CRC32 below covers these same stored bytes).
+    len: u64,
+    /// CRC32 checksum of the stored payload.
+    crc32: u32,
+    /// Whether the payload was written in packed raw-RGB mode.
+    packed: bool,
+    /// Side length of the pixel square each payload byte was written as.
+    /// `1` means no block encoding was used.
+    block_size: u8,
+    /// Whether the stored payload is DEFLATE-compressed and must be
+    /// inflated after the CRC32 check to recover the original bytes.
+    compressed: bool,
+}
+
+impl Header {
+    fn to_bytes(&self) -> [u8; HEADER_LEN] {
+        let mut bytes = [0u8; HEADER_LEN];
+        bytes[0..4].copy_from_slice(&HEADER_MAGIC);
+        bytes[4..12].copy_from_slice(&self.len.to_le_bytes());
+        bytes[12..16].copy_from_slice(&self.crc32.to_le_bytes());
+        let mut flags = if self.packed { FLAG_PACKED } else { 0 };
+        flags |= if self.compressed { FLAG_COMPRESSED } else { 0 };
+        bytes[16] = flags;
+        bytes[17] = self.block_size;
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Header, PicbinError> {
+        if bytes.len() < HEADER_LEN || bytes[0..4] != HEADER_MAGIC {
+            return Err(PicbinError::InvalidHeader);
+        }
+        let len = u64::from_le_bytes(bytes[4..12].try_into().unwrap());
+        let crc32 = u32::from_le_bytes(bytes[12..16].try_into().unwrap());
+        let packed = bytes[16] & FLAG_PACKED != 0;
+        let compressed = bytes[16] & FLAG_COMPRESSED != 0;
+        let block_size = bytes[17].max(1);
+        Ok(Header { len, crc32, packed, block_size, compressed })
+    }
+}
+
+/// DEFLATE-compress `data`.
+fn deflate(data: &[u8]) -> Result<Vec<u8>, PicbinError> {
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data)?;
+    Ok(encoder.finish()?)
+}
+
+/// Inflate a DEFLATE-compressed buffer back into its original bytes.
+fn inflate(data: &[u8]) -> Result<Vec<u8>, PicbinError> {
+    let mut decoder = DeflateDecoder::new(data);
+    let mut out = Vec::new();
+    decoder.read_to_end(&mut out)?;
+    Ok(out)
+}
+
 /// Calculate appropriate dimensions of an image.
 fn dimensions(filesize: u64) -> Result<(u32, u32), PicbinError> {
-    let width = {
-        // u64::MAX can't be handled in u32::MAX * u32::MAX
-        if filesize > (u32::MAX as u64) * (u32::MAX as u64) {
-            return Err(PicbinError::FileSizeTooLarge);
-        }
-        (filesize as f64).sqrt().ceil() as u32
-    };
-    let height = (filesize as f64 / (width as f64)).ceil() as u32;
+    // u64::MAX can't be handled in u32::MAX * u32::MAX
+    if filesize > (u32::MAX as u64) * (u32::MAX as u64) {
+        return Err(PicbinError::FileSizeTooLarge);
+    }
+    if filesize == 0 {
+        return Ok((0, 0));
+    }
+    let width = integer_sqrt_ceil(filesize);
+    let height = filesize.div_ceil(width as u64) as u32;
     Ok((width, height))
 }
 
+/// Smallest `width` such that `width * width >= n`, computed with integer
+/// arithmetic only. `f64` only has 52 bits of mantissa, so for large `n`
+/// `(n as f64).sqrt()` can be off by one and produce a width too small to
+/// hold every byte.
+fn integer_sqrt_ceil(n: u64) -> u32 {
+    let mut x = n;
+    loop {
+        let next = (x + n / x) / 2;
+        if next >= x {
+            break;
+        }
+        x = next;
+    }
+    if x * x < n {
+        x += 1;
+    }
+    x as u32
+}
+
+/// Verify that an RGB image buffer of `width` x `height` pixels can be
+/// allocated without overflowing `usize`, mirroring the buffer-size
+/// overflow guard well-behaved image decoders apply before trusting
+/// untrusted dimensions.
+fn checked_image_buffer_size(width: u32, height: u32) -> Result<usize, PicbinError> {
+    (width as usize)
+        .checked_mul(height as usize)
+        .and_then(|pixels| pixels.checked_mul(3))
+        .ok_or(PicbinError::FileSizeTooLarge)
+}
+
 /// Map a single byte to color.
 fn byte_to_color(b: u8) -> image::Rgb<u8> {
     let b = b as u32;
@@ -87,7 +295,7 @@ fn byte_to_color(b: u8) -> image::Rgb<u8> {
     let offset = b % 60;
 
     let secondary_inc = (256 * offset / 60) as u8;
-    let secondary_dec = (255 - secondary_inc) as u8;
+    let secondary_dec = 255 - secondary_inc;
     let vals: [u8; 3] = match hue_section_idx {
         0 => [255, secondary_inc, 0],
         1 => [secondary_dec, 255, 0],
@@ -100,42 +308,162 @@ fn byte_to_color(b: u8) -> image::Rgb<u8> {
     image::Rgb(vals)
 }
 
-/// Encode the given content into an image.
-fn encode_to_image(f: &mut fs::File) -> Result<image::RgbImage, PicbinError> {
-    // decide the dimensions of an image based on file size
-    let filesize = match f.metadata() {
-        Ok(v) => v.len(),
-        Err(e)  => return Err(PicbinError::IO(e)),
+/// Find the byte whose `byte_to_color` value is closest to `target` in
+/// Euclidean RGB distance. Used to recover a payload when the pixel was
+/// perturbed by lossy compression or resampling and no longer matches any
+/// color exactly.
+fn nearest_byte(target: &image::Rgb<u8>) -> u8 {
+    let dist = |rgb: image::Rgb<u8>| -> i32 {
+        (0..3)
+            .map(|c| {
+                let d = rgb[c] as i32 - target[c] as i32;
+                d * d
+            })
+            .sum()
     };
-    let (width, height) = dimensions(filesize)?;
+    (u8::MIN..=u8::MAX)
+        .min_by_key(|&b| dist(byte_to_color(b)))
+        .unwrap()
+}
 
-    let mut img = image::RgbImage::new(width, height);
-    let reader = BufReader::new(f);
-    for (i, b) in reader.bytes().into_iter().enumerate() {
-        // read each byte
-        let b = match b {
-            Ok(v) => v,
-            Err(e) => return Err(PicbinError::IO(e)),
+/// Average a block of pixels channel-wise into a single color.
+fn average_pixel<'a>(pixels: impl Iterator<Item = &'a image::Rgb<u8>>) -> image::Rgb<u8> {
+    let (mut sum, mut count) = ([0u32; 3], 0u32);
+    for rgb in pixels {
+        for c in 0..3 {
+            sum[c] += rgb[c] as u32;
+        }
+        count += 1;
+    }
+    image::Rgb(sum.map(|s| (s / count.max(1)) as u8))
+}
+
+/// Compute the pixel grid for the block layout: `n`x`n` squares of payload
+/// bytes below a flat, one-pixel-per-byte header. Returns `(width, height,
+/// bw, header_rows)`, where `bw` is the block grid's width in blocks and
+/// `header_rows` is how many header rows precede the block grid. All math
+/// happens in `u64` and is checked before narrowing back to `u32`, so a
+/// large payload combined with a large block size is rejected as
+/// `FileSizeTooLarge` instead of silently wrapping the u32 dimensions.
+fn block_layout_dimensions(payload_len: u64, block_size: u8) -> Result<(u32, u32, u32, u32), PicbinError> {
+    let n = block_size as u64;
+    let (bw_min, bh) = dimensions(payload_len)?;
+    let bw = (bw_min as u64).max((HEADER_LEN as u64).div_ceil(n));
+    let width = bw.checked_mul(n).ok_or(PicbinError::FileSizeTooLarge)?;
+    let header_rows = (HEADER_LEN as u64).div_ceil(width);
+    let height = header_rows
+        .checked_add((bh as u64).checked_mul(n).ok_or(PicbinError::FileSizeTooLarge)?)
+        .ok_or(PicbinError::FileSizeTooLarge)?;
+    let width: u32 = width.try_into().map_err(|_| PicbinError::FileSizeTooLarge)?;
+    let height: u32 = height.try_into().map_err(|_| PicbinError::FileSizeTooLarge)?;
+    // bw and header_rows fit u32 too: each is a factor or addend of
+    // width/height, which have just been shown to fit
+    Ok((width, height, bw as u32, header_rows as u32))
+}
+
+/// Encode the given content into an image. In `packed` mode, three payload
+/// bytes are written directly into each pixel's R, G and B channels instead
+/// of mapping one byte per pixel through [`byte_to_color`], tripling storage
+/// density at the cost of no longer surviving lossy recompression.
+fn encode_to_image(
+    f: &mut fs::File,
+    packed: bool,
+    block_size: u8,
+    compress: bool,
+) -> Result<image::RgbImage, PicbinError> {
+    // read the whole payload up front: we need its exact length and CRC32
+    // before we can size the image or write the header
+    let mut payload = Vec::new();
+    BufReader::new(f).read_to_end(&mut payload)?;
+    if compress {
+        payload = deflate(&payload)?;
+    }
+
+    // block encoding only applies to the hue-mapped payload; packed mode
+    // already writes raw channel bytes, so a block square would not help
+    let block_size = if packed { 1 } else { block_size };
+
+    let header = Header {
+        len: payload.len() as u64,
+        crc32: crc32(&payload),
+        packed,
+        block_size,
+        compressed: compress,
+    };
+
+    if block_size <= 1 {
+        // flat layout: header and payload each occupy one pixel per byte
+        // (three bytes per pixel in packed mode)
+        let payload_pixels = if packed {
+            (payload.len() as u64).div_ceil(3)
+        } else {
+            payload.len() as u64
+        };
+        let (width, height) = dimensions(HEADER_LEN as u64 + payload_pixels)?;
+        checked_image_buffer_size(width, height)?;
+        let mut img = image::RgbImage::new(width, height);
+        let mut put = |i: u64, px: image::Rgb<u8>| {
+            let x = (i % (width as u64)) as u32;
+            let y = (i / (width as u64)) as u32;
+            img.put_pixel(x, y, px)
         };
-        // ...and put it as a pixel on the image
+        for (i, b) in header.to_bytes().into_iter().enumerate() {
+            put(i as u64, byte_to_color(b));
+        }
+        if packed {
+            for (i, chunk) in payload.chunks(3).enumerate() {
+                // the last chunk may be short; zero-pad it, its true length
+                // is recovered from the header
+                let mut rgb = [0u8; 3];
+                rgb[..chunk.len()].copy_from_slice(chunk);
+                put(HEADER_LEN as u64 + i as u64, image::Rgb(rgb));
+            }
+        } else {
+            for (i, &b) in payload.iter().enumerate() {
+                put(HEADER_LEN as u64 + i as u64, byte_to_color(b));
+            }
+        }
+        return Ok(img);
+    }
+
+    // block layout: the header still occupies one pixel per byte so it can
+    // be read back before the block size is known to matter, but every
+    // payload byte becomes an NxN square of identical pixels underneath it
+    let n = block_size as u32;
+    let (width, height, bw, header_rows) = block_layout_dimensions(payload.len() as u64, block_size)?;
+
+    checked_image_buffer_size(width, height)?;
+    let mut img = image::RgbImage::new(width, height);
+    for (i, b) in header.to_bytes().into_iter().enumerate() {
+        let i = i as u32;
+        img.put_pixel(i % width, i / width, byte_to_color(b));
+    }
+    for (i, &b) in payload.iter().enumerate() {
+        let i = i as u32;
+        let (bx, by) = (i % bw, i / bw);
         let px = byte_to_color(b);
-        let i = i as u64;
-        let x = (i % (width as u64)) as u32;
-        let y = (i / (width as u64)) as u32;
-        img.put_pixel(x, y, px)
+        for dy in 0..n {
+            for dx in 0..n {
+                img.put_pixel(bx * n + dx, header_rows + by * n + dy, px);
+            }
+        }
     }
     Ok(img)
 }
 
 /// Decode the original binary content from the given image.
-fn decode_from_image(image: &mut fs::File, out: &mut fs::File) -> Result<(), PicbinError>{
+fn decode_from_image(image: &mut fs::File, out: &mut fs::File, nearest: bool) -> Result<(), PicbinError>{
     // prepare inverse mapping, from color to byte
     let mut pixel_to_byte= HashMap::new();
     for i in u8::MIN..=u8::MAX {
         pixel_to_byte.insert(byte_to_color(i), i);
     }
-    // prepare destination
-    let mut writer = BufWriter::new(out);
+    // when `nearest` is set, fall back to the closest known color instead
+    // of giving up on a pixel that isn't an exact match
+    let decode_pixel = |rgb: &image::Rgb<u8>| -> Option<u8> {
+        pixel_to_byte.get(rgb).copied().or_else(|| nearest.then(|| nearest_byte(rgb)))
+    };
+
     // prepare an image
     let reader = match image::io::Reader::new(BufReader::new(image)).with_guessed_format() {
         Ok(v) => v,
@@ -145,18 +473,84 @@ fn decode_from_image(image: &mut fs::File, out: &mut fs::File) -> Result<(), Pic
         Ok(v) => v,
         Err(e) => return Err(PicbinError::Imaging(e)),
     };
-    // read each pixel and decode to a single byte
-    for rgb in img.to_rgb8().pixels() {
-        match pixel_to_byte.get(rgb) {
-            Some(&b) => {
-                match writer.write(&[b]) {
-                    Ok(_) => {},
-                    Err(e) => return Err(PicbinError::IO(e)),
+    let rgb_img = img.to_rgb8();
+    let width = rgb_img.width();
+    let pixels: Vec<&image::Rgb<u8>> = rgb_img.pixels().collect();
+
+    // the header always goes through the (optionally nearest-matched) hue
+    // mapping, regardless of which mode the payload itself uses
+    if pixels.len() < HEADER_LEN {
+        return Err(PicbinError::InvalidHeader);
+    }
+    let mut header_bytes = Vec::with_capacity(HEADER_LEN);
+    for rgb in &pixels[..HEADER_LEN] {
+        match decode_pixel(rgb) {
+            Some(b) => header_bytes.push(b),
+            None => return Err(PicbinError::InvalidHeader),
+        }
+    }
+    let header = Header::from_bytes(&header_bytes)?;
+
+    // don't preallocate with `header.len`: it comes straight from the file
+    // and a corrupt or hand-crafted header could claim an enormous length,
+    // aborting the process on `capacity overflow` before the CRC32 check
+    // below gets a chance to reject it. The loops already bound growth by
+    // `pixels.len()`.
+    let mut payload = Vec::new();
+    if header.packed {
+        for rgb in &pixels[HEADER_LEN..] {
+            payload.extend_from_slice(&rgb.0);
+            if payload.len() >= header.len as usize {
+                break;
+            }
+        }
+    } else if header.block_size <= 1 {
+        for rgb in &pixels[HEADER_LEN..] {
+            if let Some(b) = decode_pixel(rgb) {
+                payload.push(b);
+                if payload.len() >= header.len as usize {
+                    break;
                 }
-            },
-            None => continue,
+            }
         }
+    } else {
+        // block layout: average each NxN square of pixels underneath a byte,
+        // then pick the nearest color, so moderate JPEG artifacts and
+        // downscaling average out instead of corrupting the byte
+        let n = header.block_size as u32;
+        let bw = (width / n).max(1);
+        let header_rows = (HEADER_LEN as u32).div_ceil(width);
+        // decode no more blocks than the image holds; a payload that comes
+        // up short of `header.len` is rejected below
+        let bh = rgb_img.height().saturating_sub(header_rows) / n;
+        for i in 0..header.len.min(bw as u64 * bh as u64) {
+            let i = i as u32;
+            let (bx, by) = (i % bw, i / bw);
+            let mut block = Vec::with_capacity((n * n) as usize);
+            for dy in 0..n {
+                for dx in 0..n {
+                    let (x, y) = (bx * n + dx, header_rows + by * n + dy);
+                    if let Some(&rgb) = pixels.get((y * width + x) as usize) {
+                        block.push(rgb);
+                    }
+                }
+            }
+            payload.push(nearest_byte(&average_pixel(block.into_iter())));
+        }
+    }
+    if (payload.len() as u64) < header.len {
+        return Err(PicbinError::InvalidHeader);
+    }
+    payload.truncate(header.len as usize);
+    if crc32(&payload) != header.crc32 {
+        return Err(PicbinError::ChecksumMismatch);
+    }
+    if header.compressed {
+        payload = inflate(&payload)?;
     }
+
+    let mut writer = BufWriter::new(out);
+    writer.write_all(&payload)?;
     Ok(())
 }
 
@@ -180,22 +574,32 @@ fn cli() -> Result<(), PicbinError> {
 
     match &cli.command {
 
-        Commands::Encode { bin, dst } => {
+        Commands::Encode { bin, dst, packed, block_size, compress, format } => {
             if Path::exists(Path::new(dst)) && !cli.overwrite {
                 return Err(PicbinError::DestinationExists(dst.to_string()))
             }
-            let mut original_file = fs::File::open(&bin)?;
-            let encoded = encode_to_image(&mut original_file)?;
-            encoded.save(&dst)?;
+            let resolved_format = match format {
+                Some(f) => f.image_format(),
+                None => image::ImageFormat::from_path(dst)?,
+            };
+            // blocks average out lossy artifacts; without them, the
+            // exact-match round trip can't survive a lossy codec
+            let tolerates_lossy = !*packed && *block_size > 1;
+            if !image_format_is_lossless(resolved_format) && !tolerates_lossy {
+                return Err(PicbinError::LossyFormatUnsupported(resolved_format));
+            }
+            let mut original_file = fs::File::open(bin)?;
+            let encoded = encode_to_image(&mut original_file, *packed, *block_size, *compress)?;
+            encoded.save_with_format(dst, resolved_format)?;
         },
 
-        Commands::Decode { img, dst } => {
+        Commands::Decode { img, dst, nearest } => {
             if Path::exists(Path::new(dst)) && !cli.overwrite {
                 return Err(PicbinError::DestinationExists(dst.to_string()))
             }
-            let mut encoded_file = fs::File::open(&img)?;
-            let mut decoded_file = fs::File::create(&dst)?;
-            decode_from_image(&mut encoded_file, &mut decoded_file)?;
+            let mut encoded_file = fs::File::open(img)?;
+            let mut decoded_file = fs::File::create(dst)?;
+            decode_from_image(&mut encoded_file, &mut decoded_file, *nearest)?;
         },
 
         Commands::ColorChart => print_colorchart(),
@@ -216,8 +620,13 @@ fn main() -> ExitCode {
 
 #[cfg(test)]
 mod tests {
-    use crate::{dimensions, byte_to_color};
+    use crate::{
+        average_pixel, block_layout_dimensions, checked_image_buffer_size, crc32,
+        decode_from_image, deflate, dimensions, byte_to_color, encode_to_image,
+        image_format_is_lossless, inflate, nearest_byte, Header,
+    };
     use std::collections::HashSet;
+    use std::fs;
 
     #[test]
     fn test_dimensions() {
@@ -226,6 +635,33 @@ mod tests {
         assert!(dimensions((u32::MAX as u64) * (u32::MAX as u64) + 1).is_err());
     }
 
+    #[test]
+    fn dimensions_are_integer_exact() {
+        // f64 only has 52 bits of mantissa, so `n as f64` is no longer
+        // exact for the two largest values; the integer version must still
+        // give the minimal width that fits every byte
+        for n in [1u64, 2, 3, 1_000_000, (1u64 << 53) + 1, (u32::MAX as u64) * (u32::MAX as u64)] {
+            let (width, height) = dimensions(n).unwrap();
+            assert!((width as u64) * (height as u64) >= n, "too small for {n}");
+            assert!(width == 1 || ((width - 1) as u64).pow(2) < n, "width not minimal for {n}");
+        }
+    }
+
+    #[test]
+    fn checked_image_buffer_size_rejects_overflow() {
+        assert!(checked_image_buffer_size(u32::MAX, u32::MAX).is_err());
+        assert_eq!(checked_image_buffer_size(2, 3).unwrap(), 18);
+    }
+
+    #[test]
+    fn jpeg_is_lossy_and_allowlisted_formats_are_lossless() {
+        assert!(!image_format_is_lossless(image::ImageFormat::Jpeg));
+        assert!(image_format_is_lossless(image::ImageFormat::Png));
+        assert!(image_format_is_lossless(image::ImageFormat::Bmp));
+        assert!(image_format_is_lossless(image::ImageFormat::Tiff));
+        assert!(image_format_is_lossless(image::ImageFormat::WebP));
+    }
+
     #[test]
     fn unique_color_mapping() {
         let mut set = HashSet::new();
@@ -234,4 +670,198 @@ mod tests {
         }
         assert_eq!(set.len(), 256);
     }
+
+    #[test]
+    fn crc32_known_vector() {
+        // CRC32 of the ASCII string "123456789" is a well-known test vector
+        assert_eq!(crc32(b"123456789"), 0xCBF43926);
+    }
+
+    #[test]
+    fn header_roundtrip() {
+        let header = Header {
+            len: 42,
+            crc32: 0xDEADBEEF,
+            packed: true,
+            block_size: 8,
+            compressed: true,
+        };
+        let bytes = header.to_bytes();
+        let parsed = Header::from_bytes(&bytes).unwrap();
+        assert_eq!(parsed.len, 42);
+        assert_eq!(parsed.crc32, 0xDEADBEEF);
+        assert!(parsed.packed);
+        assert_eq!(parsed.block_size, 8);
+        assert!(parsed.compressed);
+    }
+
+    #[test]
+    fn header_rejects_bad_magic() {
+        let bytes = [0u8; crate::HEADER_LEN];
+        assert!(Header::from_bytes(&bytes).is_err());
+    }
+
+    #[test]
+    fn nearest_byte_finds_exact_match() {
+        for i in u8::MIN..=u8::MAX {
+            assert_eq!(nearest_byte(&byte_to_color(i)), i);
+        }
+    }
+
+    #[test]
+    fn nearest_byte_tolerates_small_perturbation() {
+        let color = byte_to_color(128);
+        let nudged = image::Rgb([
+            color[0].saturating_add(1),
+            color[1],
+            color[2].saturating_sub(1),
+        ]);
+        assert_eq!(nearest_byte(&nudged), 128);
+    }
+
+    #[test]
+    fn average_pixel_averages_channels() {
+        let a = image::Rgb([0u8, 10, 20]);
+        let b = image::Rgb([10u8, 20, 0]);
+        assert_eq!(average_pixel([&a, &b].into_iter()), image::Rgb([5, 15, 10]));
+    }
+
+    #[test]
+    fn deflate_roundtrip() {
+        let data = b"hello hello hello picbin picbin picbin".repeat(4);
+        let compressed = deflate(&data).unwrap();
+        assert!(compressed.len() < data.len());
+        assert_eq!(inflate(&compressed).unwrap(), data);
+    }
+
+    #[test]
+    fn block_layout_dimensions_rejects_overflow() {
+        // a payload whose minimal width, times a near-u8::MAX block size,
+        // would overflow u32 arithmetic before narrowing back down
+        assert!(block_layout_dimensions((u32::MAX as u64) * (u32::MAX as u64), 255).is_err());
+        let (width, height, bw, header_rows) = block_layout_dimensions(100, 4).unwrap();
+        assert!(width >= bw * 4);
+        assert!(height >= header_rows);
+    }
+
+    /// Encode `data` then decode it back, through the same public entry
+    /// points and on-disk PNG round trip the CLI uses, asserting the
+    /// recovered bytes match exactly.
+    fn roundtrip(name: &str, data: &[u8], packed: bool, block_size: u8, compress: bool, nearest: bool) {
+        let dir = std::env::temp_dir();
+        let src_path = dir.join(format!("picbin_test_{name}_src.bin"));
+        let img_path = dir.join(format!("picbin_test_{name}.png"));
+        let dst_path = dir.join(format!("picbin_test_{name}_dst.bin"));
+
+        fs::write(&src_path, data).unwrap();
+        let mut src_file = fs::File::open(&src_path).unwrap();
+        let img = encode_to_image(&mut src_file, packed, block_size, compress).unwrap();
+        img.save(&img_path).unwrap();
+
+        let mut img_file = fs::File::open(&img_path).unwrap();
+        let mut dst_file = fs::File::create(&dst_path).unwrap();
+        decode_from_image(&mut img_file, &mut dst_file, nearest).unwrap();
+
+        assert_eq!(fs::read(&dst_path).unwrap(), data);
+
+        fs::remove_file(&src_path).unwrap();
+        fs::remove_file(&img_path).unwrap();
+        fs::remove_file(&dst_path).unwrap();
+    }
+
+    #[test]
+    fn roundtrip_plain() {
+        roundtrip("plain", b"hello, picbin!", false, 1, false, false);
+    }
+
+    #[test]
+    fn roundtrip_packed() {
+        // not a multiple of 3, to exercise the zero-padded last pixel
+        roundtrip("packed", b"hello, picbin", true, 1, false, false);
+    }
+
+    #[test]
+    fn roundtrip_block_and_nearest() {
+        roundtrip("block", b"hello, picbin!", false, 4, false, true);
+    }
+
+    #[test]
+    fn roundtrip_compressed() {
+        let data = b"hello hello hello picbin picbin picbin".repeat(4);
+        roundtrip("compressed", &data, false, 1, true, false);
+    }
+
+    #[test]
+    fn roundtrip_block_survives_perturbed_pixels() {
+        // nearest_byte_tolerates_small_perturbation already covers a ±1
+        // nudge; this drives the same fallback through the real block
+        // decode path with a perturbation large enough (and block size
+        // large enough) that an exact-match or single-pixel decode would
+        // fail, simulating the moderate recompression/resampling noise
+        // --block-size/--nearest are meant to survive.
+        let data = b"hello, picbin!";
+        let block_size = 8u8;
+
+        let dir = std::env::temp_dir();
+        let src_path = dir.join("picbin_test_perturbed_src.bin");
+        let img_path = dir.join("picbin_test_perturbed.png");
+        let dst_path = dir.join("picbin_test_perturbed_dst.bin");
+
+        fs::write(&src_path, data).unwrap();
+        let mut src_file = fs::File::open(&src_path).unwrap();
+        let mut img = encode_to_image(&mut src_file, false, block_size, false).unwrap();
+
+        // nudge every payload pixel (but not the flat header) by up to
+        // ±10 per channel, alternating sign so it doesn't cancel out when
+        // blocks are averaged
+        let width = img.width();
+        let header_rows = (crate::HEADER_LEN as u32).div_ceil(width);
+        for (i, px) in img.pixels_mut().enumerate() {
+            if (i as u32) < header_rows * width {
+                continue;
+            }
+            let delta = if i % 2 == 0 { 10i16 } else { -10i16 };
+            for c in px.0.iter_mut() {
+                *c = (*c as i16 + delta).clamp(0, 255) as u8;
+            }
+        }
+        img.save(&img_path).unwrap();
+
+        let mut img_file = fs::File::open(&img_path).unwrap();
+        let mut dst_file = fs::File::create(&dst_path).unwrap();
+        decode_from_image(&mut img_file, &mut dst_file, true).unwrap();
+        assert_eq!(fs::read(&dst_path).unwrap(), data);
+
+        fs::remove_file(&src_path).unwrap();
+        fs::remove_file(&img_path).unwrap();
+        fs::remove_file(&dst_path).unwrap();
+    }
+
+    #[test]
+    fn decode_rejects_corrupt_header_len_without_panicking() {
+        // a header whose claimed length vastly exceeds what any number of
+        // pixels could hold used to make decode_from_image preallocate a
+        // Vec with that capacity and abort the process instead of
+        // returning a typed error
+        let mut img = image::RgbImage::new(crate::HEADER_LEN as u32, 1);
+        let mut header_bytes = [0u8; crate::HEADER_LEN];
+        header_bytes[0..4].copy_from_slice(&crate::HEADER_MAGIC);
+        header_bytes[4..12].copy_from_slice(&(1u64 << 63).to_le_bytes());
+        header_bytes[17] = 1; // block_size
+        for (i, &b) in header_bytes.iter().enumerate() {
+            img.put_pixel(i as u32, 0, byte_to_color(b));
+        }
+
+        let dir = std::env::temp_dir();
+        let img_path = dir.join("picbin_test_corrupt_header_len.png");
+        let dst_path = dir.join("picbin_test_corrupt_header_len.out");
+        img.save(&img_path).unwrap();
+
+        let mut img_file = fs::File::open(&img_path).unwrap();
+        let mut dst_file = fs::File::create(&dst_path).unwrap();
+        assert!(decode_from_image(&mut img_file, &mut dst_file, false).is_err());
+
+        fs::remove_file(&img_path).unwrap();
+        fs::remove_file(&dst_path).unwrap();
+    }
 }